@@ -1,52 +1,143 @@
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
-use portable_pty::{native_pty_system, CommandBuilder, PtySize};
+use portable_pty::{native_pty_system, Child, CommandBuilder, MasterPty, PtySize};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
 use std::thread;
 use tauri::{Emitter, Manager};
 
+/// The running toke child, shared between the reader thread (which waits on
+/// it to report the exit status) and the `stop_toke`/`restart_toke` commands
+/// (which need to kill it), mirroring the `SharedChild` wrapper tauri-cli
+/// uses around its dev-command children.
+type SharedChild = Arc<Mutex<Box<dyn Child + Send + Sync>>>;
+
+/// Identifies one of the possibly-many concurrent toke sessions; handed back
+/// from `start_toke` and threaded through every other PTY command so each
+/// session can be written to, resized, and killed independently.
+type SessionId = String;
+
+/// Everything a single toke terminal needs: the PTY master (for resizing),
+/// its writer half, and the child so it can be stopped or waited on. Each
+/// field owns its own lock rather than sharing the map-level `PtyState`
+/// mutex, so a write or resize on one session never blocks another.
+struct Session {
+    master: Mutex<Box<dyn MasterPty + Send>>,
+    writer: Mutex<Box<dyn Write + Send>>,
+    child: SharedChild,
+}
+
+type SharedSession = Arc<Session>;
+
+#[derive(Default)]
 struct PtyState {
-    writer: Option<Box<dyn Write + Send>>,
+    sessions: HashMap<SessionId, SharedSession>,
+    next_session_id: AtomicU64,
 }
 
-fn find_toke_dev() -> Result<String, String> {
-    eprintln!("Current directory: {:?}", std::env::current_dir());
-    
-    let toke_paths = vec![
-        "../../build/toke-darwin-arm64/toke",
-        "../build/toke-darwin-arm64/toke",
-        "../build/Toke.app/Contents/MacOS/toke",
-        "/Users/cd/github/orgs/toke/build/toke-darwin-arm64/toke",
-        "/usr/local/bin/toke",
-        "toke",
-    ];
-    
-    toke_paths
-        .iter()
-        .find_map(|p| {
-            let path = std::path::Path::new(p);
-            if path.exists() {
-                // Convert to absolute path for portable-pty
-                let abs_path = if path.is_relative() {
-                    std::env::current_dir().ok()?.join(path).canonicalize().ok()
-                } else {
-                    path.canonicalize().ok()
-                };
-                eprintln!("Found toke at: {:?}", abs_path);
-                abs_path.map(|p| p.to_string_lossy().to_string())
-            } else {
-                eprintln!("Path not found: {}", p);
-                None
-            }
-        })
-        .ok_or_else(|| "Could not find toke binary".to_string())
+impl PtyState {
+    fn next_id(&self) -> SessionId {
+        format!("toke-{}", self.next_session_id.fetch_add(1, Ordering::SeqCst))
+    }
+
+    /// Clones out the `Arc<Session>` for `session_id` so callers can do
+    /// per-session I/O without holding the map-level lock.
+    fn get_session(&self, session_id: &str) -> Result<SharedSession, String> {
+        self.sessions
+            .get(session_id)
+            .cloned()
+            .ok_or_else(|| format!("No toke session with id {session_id}"))
+    }
+}
+
+/// User-editable settings for locating and launching `toke`, persisted
+/// across reinstalls and machines via `confy` instead of relying on
+/// hard-coded path probing or bundled-resource layout.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct AppConfig {
+    toke_path: Option<PathBuf>,
+    shell: Option<String>,
+    extra_env: Vec<(String, String)>,
+    working_dir: Option<PathBuf>,
+}
+
+fn load_config() -> AppConfig {
+    confy::load("toke-tauri", "config").unwrap_or_else(|e| {
+        eprintln!("Failed to load config, using defaults: {}", e);
+        AppConfig::default()
+    })
+}
+
+fn save_config(config: &AppConfig) -> Result<(), String> {
+    confy::store("toke-tauri", "config", config).map_err(|e| e.to_string())
 }
 
 #[tauri::command]
-fn start_toke(
+fn get_config() -> AppConfig {
+    load_config()
+}
+
+#[tauri::command]
+fn set_config(config: AppConfig) -> Result<(), String> {
+    save_config(&config)
+}
+
+/// Resolves the on-disk path of a Tauri `externalBin` sidecar. The bundler
+/// copies `<name>-<target-triple>(.exe)` next to the app binary (into the
+/// resource dir for bundled builds, next to the dev binary under `tauri
+/// dev`), so the same lookup works unmodified across platforms and
+/// architectures instead of the old macOS/arm64-only path probing.
+fn resolve_sidecar(app_handle: &tauri::AppHandle, name: &str) -> Result<PathBuf, String> {
+    let target_triple = tauri::utils::platform::target_triple().map_err(|e| e.to_string())?;
+    let file_name = if cfg!(windows) {
+        format!("{name}-{target_triple}.exe")
+    } else {
+        format!("{name}-{target_triple}")
+    };
+
+    [
+        app_handle.path().resource_dir().ok(),
+        std::env::current_exe()
+            .ok()
+            .and_then(|p| p.parent().map(Path::to_path_buf)),
+    ]
+    .into_iter()
+    .flatten()
+    .map(|dir| dir.join(&file_name))
+    .find(|p| p.exists())
+    .ok_or_else(|| format!("Could not find {name} sidecar binary ({file_name})"))
+}
+
+/// Single-quotes `s` for safe interpolation into a POSIX `sh -c` string,
+/// escaping embedded single quotes as `'\''`. Needed because `toke_path`/
+/// `shell` come straight from user-edited config and commonly contain
+/// spaces (e.g. macOS paths like `/Users/Jane Doe/builds/toke`).
+fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+/// Kills and removes the session tracked under `session_id`, if any, so a
+/// `stop_toke`/`restart_toke` never leaks its PTY.
+fn kill_session(state: &tauri::State<Arc<Mutex<PtyState>>>, session_id: &str) {
+    let session = state.lock().unwrap().sessions.remove(session_id);
+    if let Some(session) = session {
+        let _ = session.child.lock().unwrap().kill();
+    }
+}
+
+/// Spawns the shell+toke child for `session_id` and installs it into
+/// `state`. Shared by `start_toke` (fresh id) and `restart_toke` (reuses the
+/// id it was given) so a restarted session keeps the same
+/// `pty-output::{id}`/`pty-exit::{id}` event names a caller already
+/// subscribed to.
+fn spawn_session(
     app_handle: tauri::AppHandle,
     state: tauri::State<Arc<Mutex<PtyState>>>,
+    session_id: SessionId,
     cols: u16,
     rows: u16,
 ) -> Result<(), String> {
@@ -61,43 +152,59 @@ fn start_toke(
         })
         .map_err(|e| e.to_string())?;
 
-    // Find toke binary - check bundled resource first, then other locations
-    let (toke_path, toke_dir) = if let Ok(resource_path) = app_handle.path().resource_dir() {
-        // In production, use the bundled toke binary
-        // Due to the relative path in resources, it's nested under _up_/_up_/build/
-        let bundled_dir = resource_path.join("_up_").join("_up_").join("build").join("toke-darwin-arm64");
-        let bundled_toke = bundled_dir.join("toke");
-        if bundled_toke.exists() {
-            eprintln!("Using bundled toke at: {:?}", bundled_toke);
-            (bundled_toke.to_string_lossy().to_string(), Some(bundled_dir))
-        } else {
-            // Try without the nested path (for future cleaner builds)
-            let alt_bundled_dir = resource_path.join("toke-darwin-arm64");
-            let alt_bundled_toke = alt_bundled_dir.join("toke");
-            if alt_bundled_toke.exists() {
-                eprintln!("Using bundled toke at: {:?}", alt_bundled_toke);
-                (alt_bundled_toke.to_string_lossy().to_string(), Some(alt_bundled_dir))
-            } else {
-                // Fallback for development
-                (find_toke_dev()?, None)
-            }
-        }
+    let config = load_config();
+
+    // Find toke binary - check the user-configured path first, then the
+    // toke sidecar shipped next to the app
+    let (toke_path, toke_dir) = if let Some(configured) = config
+        .toke_path
+        .as_ref()
+        .filter(|p| p.exists())
+    {
+        eprintln!("Using configured toke at: {:?}", configured);
+        (configured.to_string_lossy().to_string(), None)
     } else {
-        // Development mode
-        (find_toke_dev()?, None)
+        let sidecar = resolve_sidecar(&app_handle, "toke")?;
+        eprintln!("Using toke sidecar at: {:?}", sidecar);
+        let sidecar_dir = sidecar.parent().map(Path::to_path_buf);
+        (sidecar.to_string_lossy().to_string(), sidecar_dir)
     };
-    
+
     eprintln!("Using toke binary at: {}", toke_path);
 
     // Start a shell that runs toke, so when toke exits, user lands in shell
-    let shell = std::env::var("SHELL").unwrap_or_else(|_| "/bin/bash".to_string());
+    let shell = config
+        .shell
+        .clone()
+        .or_else(|| std::env::var("SHELL").ok())
+        .unwrap_or_else(|| "/bin/bash".to_string());
     let mut cmd = CommandBuilder::new(&shell);
     cmd.env("TERM", "xterm-256color");
-    
+    for (key, value) in &config.extra_env {
+        cmd.env(key, value);
+    }
+
+    // ngrok ships as its own externalBin sidecar alongside toke; resolve it
+    // and hand toke its absolute path so tunnel support works regardless of
+    // cwd. It's optional, so a missing ngrok sidecar only disables tunneling.
+    match resolve_sidecar(&app_handle, "ngrok") {
+        Ok(ngrok_path) => {
+            eprintln!("Using ngrok sidecar at: {:?}", ngrok_path);
+            cmd.env("NGROK_PATH", ngrok_path);
+        }
+        Err(e) => eprintln!("ngrok sidecar not available ({e}); toke will run without tunnel support"),
+    }
+
     // If we have a bundled toke directory, set it as the working directory
-    // so toke can find its backends and ngrok
-    let use_bundled = toke_dir.is_some();
-    if let Some(dir) = toke_dir {
+    // so toke can find its backends; an explicit config working_dir takes
+    // precedence over that. The relative `./toke` is only used when it names
+    // the same file as `toke_path`; a sidecar's file name carries the target
+    // triple, so it is run by its full path.
+    let use_bundled = config.working_dir.is_none()
+        && toke_dir
+            .as_ref()
+            .map_or(false, |dir| dir.join("toke") == Path::new(&toke_path));
+    if let Some(dir) = config.working_dir.clone().or(toke_dir) {
         cmd.cwd(dir);
         eprintln!("Setting working directory for bundled resources");
     }
@@ -111,23 +218,39 @@ fn start_toke(
     };
     cmd.arg(format!(
         "{} || true; printf '\\033[2J\\033[H\\033[?25h'; clear; exec {}",
-        toke_cmd, shell
+        shell_quote(&toke_cmd),
+        shell_quote(&shell)
     ));
     
-    let mut child = pty_pair
+    let child = pty_pair
         .slave
         .spawn_command(cmd)
         .map_err(|e| e.to_string())?;
+    let child: SharedChild = Arc::new(Mutex::new(child));
 
     let mut reader = pty_pair.master.try_clone_reader().map_err(|e| e.to_string())?;
     let writer = pty_pair.master.take_writer().map_err(|e| e.to_string())?;
 
-    // Store writer in state
+    // Store the master (needed to resize the PTY later), writer, and child
+    // in state, keyed by this session's id
     {
         let mut state = state.lock().unwrap();
-        state.writer = Some(writer);
+        state.sessions.insert(
+            session_id.clone(),
+            Arc::new(Session {
+                master: Mutex::new(pty_pair.master),
+                writer: Mutex::new(writer),
+                child: child.clone(),
+            }),
+        );
     }
 
+    let wait_child = child.clone();
+    let state_handle = state.inner().clone();
+    let output_event = format!("pty-output::{session_id}");
+    let exit_event = format!("pty-exit::{session_id}");
+    let wait_session_id = session_id.clone();
+
     // Read output in separate thread
     thread::spawn(move || {
         let mut buf = [0u8; 4096];
@@ -136,39 +259,100 @@ fn start_toke(
                 Ok(0) => break,
                 Ok(n) => {
                     let data = buf[..n].to_vec();
-                    let _ = app_handle.emit("pty-output", data);
+                    let _ = app_handle.emit(&output_event, data);
                 }
                 Err(_) => break,
             }
         }
-        
-        // Wait for toke to exit
-        let exit_status = child.wait();
-        eprintln!("Toke process exited with status: {:?}", exit_status);
+
+        // Wait for toke to exit and let the frontend know, so it can show a
+        // banner or offer to relaunch instead of staring at a dead terminal.
+        let exit_status = wait_child.lock().unwrap().wait();
+        eprintln!(
+            "Toke session {} exited with status: {:?}",
+            wait_session_id, exit_status
+        );
+        let exit_code = exit_status.ok().map(|s| s.exit_code());
+        let _ = app_handle.emit(&exit_event, exit_code);
+
+        // Only drop the session if it's still the one we waited on; a
+        // subsequent restart_toke may already have replaced it under the
+        // same id.
+        let mut state = state_handle.lock().unwrap();
+        if state
+            .sessions
+            .get(&wait_session_id)
+            .map_or(false, |s| Arc::ptr_eq(&s.child, &wait_child))
+        {
+            state.sessions.remove(&wait_session_id);
+        }
     });
 
     Ok(())
 }
 
 #[tauri::command]
-fn write_to_pty(state: tauri::State<Arc<Mutex<PtyState>>>, data: String) -> Result<(), String> {
-    let mut state = state.lock().unwrap();
-    if let Some(writer) = state.writer.as_mut() {
-        writer.write_all(data.as_bytes()).map_err(|e| e.to_string())?;
-        writer.flush().map_err(|e| e.to_string())?;
-    }
+fn start_toke(
+    app_handle: tauri::AppHandle,
+    state: tauri::State<Arc<Mutex<PtyState>>>,
+    cols: u16,
+    rows: u16,
+) -> Result<SessionId, String> {
+    let session_id = state.lock().unwrap().next_id();
+    spawn_session(app_handle, state, session_id.clone(), cols, rows)?;
+    Ok(session_id)
+}
+
+#[tauri::command]
+fn stop_toke(state: tauri::State<Arc<Mutex<PtyState>>>, session_id: SessionId) -> Result<(), String> {
+    kill_session(&state, &session_id);
     Ok(())
 }
 
+#[tauri::command]
+fn restart_toke(
+    app_handle: tauri::AppHandle,
+    state: tauri::State<Arc<Mutex<PtyState>>>,
+    session_id: SessionId,
+    cols: u16,
+    rows: u16,
+) -> Result<SessionId, String> {
+    kill_session(&state, &session_id);
+    spawn_session(app_handle, state, session_id.clone(), cols, rows)?;
+    Ok(session_id)
+}
+
+#[tauri::command]
+fn write_to_pty(
+    state: tauri::State<Arc<Mutex<PtyState>>>,
+    session_id: SessionId,
+    data: String,
+) -> Result<(), String> {
+    let session = state.lock().unwrap().get_session(&session_id)?;
+    let mut writer = session.writer.lock().unwrap();
+    writer.write_all(data.as_bytes()).map_err(|e| e.to_string())?;
+    writer.flush().map_err(|e| e.to_string())
+}
+
 #[tauri::command]
 fn resize_pty(
-    _state: tauri::State<Arc<Mutex<PtyState>>>,
+    state: tauri::State<Arc<Mutex<PtyState>>>,
+    session_id: SessionId,
     cols: u16,
     rows: u16,
 ) -> Result<(), String> {
-    // TODO: Implement PTY resize
-    println!("Resize to {}x{}", cols, rows);
-    Ok(())
+    let session = state.lock().unwrap().get_session(&session_id)?;
+    session
+        .master
+        .lock()
+        .unwrap()
+        .resize(PtySize {
+            rows,
+            cols,
+            pixel_width: 0,
+            pixel_height: 0,
+        })
+        .map_err(|e| e.to_string())
 }
 
 fn main() {
@@ -176,8 +360,25 @@ fn main() {
         .plugin(tauri_plugin_updater::Builder::new().build())
         .plugin(tauri_plugin_dialog::init())
         .plugin(tauri_plugin_process::init())
-        .manage(Arc::new(Mutex::new(PtyState { writer: None })))
-        .invoke_handler(tauri::generate_handler![start_toke, write_to_pty, resize_pty])
+        .manage(Arc::new(Mutex::new(PtyState::default())))
+        .invoke_handler(tauri::generate_handler![
+            start_toke,
+            stop_toke,
+            restart_toke,
+            write_to_pty,
+            resize_pty,
+            get_config,
+            set_config
+        ])
+        .on_window_event(|window, event| {
+            if let tauri::WindowEvent::CloseRequested { .. } = event {
+                let state = window.state::<Arc<Mutex<PtyState>>>();
+                let sessions = std::mem::take(&mut state.lock().unwrap().sessions);
+                for session in sessions.into_values() {
+                    let _ = session.child.lock().unwrap().kill();
+                }
+            }
+        })
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
 }